@@ -0,0 +1,230 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Persistent address manager
+//!
+//! Remembers addresses learned from `addr`/`addrv2` gossip, writing
+//! each one through to the chaindb and seeding itself from the chaindb
+//! on start. They survive a restart only if the chaindb does; the
+//! `Hammersbald` store in this crate keeps them in memory. Addresses are
+//! kept in a "new" bucket until we have connected to them successfully
+//! at least once, at which point they move to "tried". A single
+//! malicious peer gossiping garbage can only ever pollute "new".
+//!
+
+use crate::chaindb::SharedChainDB;
+use crate::dispatcher::PeerMessage;
+use crate::p2p::PeerMessageSender;
+use crate::proxy::{from_addr, from_addr_v2, NetAddress};
+use bitcoin::network::message::NetworkMessage;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Backoff applied after a failed connect attempt, doubled per failure
+/// up to `MAX_BACKOFF`
+const INITIAL_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60 * 24);
+
+#[derive(Clone, Debug)]
+struct PeerRecord {
+    last_seen: u64,
+    last_success: Option<u64>,
+    last_failure: Option<u64>,
+    failures: u32,
+    tried: bool,
+}
+
+impl PeerRecord {
+    fn new(now: u64) -> PeerRecord {
+        PeerRecord {
+            last_seen: now,
+            last_success: None,
+            last_failure: None,
+            failures: 0,
+            tried: false,
+        }
+    }
+
+    fn backoff_until(&self) -> Option<u64> {
+        self.last_failure.map(|t| {
+            let backoff = INITIAL_BACKOFF
+                .checked_mul(1 << self.failures.saturating_sub(1).min(31))
+                .unwrap_or(MAX_BACKOFF)
+                .min(MAX_BACKOFF);
+            t + backoff.as_secs()
+        })
+    }
+}
+
+/// addrman-style store of addresses learned from the network, organized
+/// into "new" (unverified gossip) and "tried" (we connected successfully
+/// at least once) buckets
+pub struct AddressManager {
+    chaindb: SharedChainDB,
+    peers: HashMap<NetAddress, PeerRecord>,
+}
+
+impl AddressManager {
+    pub fn new(chaindb: SharedChainDB) -> AddressManager {
+        let peers = chaindb
+            .read()
+            .unwrap()
+            .read_addresses()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(addr, last_seen, last_success)| {
+                (
+                    addr,
+                    PeerRecord {
+                        last_seen,
+                        last_success,
+                        last_failure: None,
+                        failures: 0,
+                        tried: last_success.is_some(),
+                    },
+                )
+            })
+            .collect();
+        AddressManager { chaindb, peers }
+    }
+
+    /// Record an address learned from `addr`/`addrv2` gossip
+    pub fn add_gossiped(&mut self, addr: NetAddress) {
+        let now = now();
+        self.peers
+            .entry(addr.clone())
+            .and_modify(|r| r.last_seen = now)
+            .or_insert_with(|| PeerRecord::new(now));
+        self.persist(&addr);
+    }
+
+    /// Record a successful connect: moves the address into "tried"
+    pub fn mark_success(&mut self, addr: NetAddress) {
+        let now = now();
+        let record = self
+            .peers
+            .entry(addr.clone())
+            .or_insert_with(|| PeerRecord::new(now));
+        record.last_success = Some(now);
+        record.failures = 0;
+        record.tried = true;
+        self.persist(&addr);
+    }
+
+    /// Record a failed connect attempt, applying exponential backoff to
+    /// future candidate selection
+    pub fn mark_failure(&mut self, addr: NetAddress) {
+        if let Some(record) = self.peers.get_mut(&addr) {
+            record.last_failure = Some(now());
+            record.failures += 1;
+        }
+        self.persist(&addr);
+    }
+
+    /// True if the store holds no address at all
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Pick a connect candidate, preferring "tried" addresses with a
+    /// recent success and skipping anything still in backoff
+    pub fn candidate(&self, tried_except: &std::collections::HashSet<NetAddress>) -> Option<NetAddress> {
+        let now = now();
+        let eligible = |r: &&PeerRecord| r.backoff_until().map_or(true, |b| b <= now);
+
+        self.peers
+            .iter()
+            .filter(|(a, r)| r.tried && eligible(r) && !tried_except.contains(*a))
+            .max_by_key(|(_, r)| r.last_success)
+            .or_else(|| {
+                self.peers
+                    .iter()
+                    .filter(|(a, r)| !r.tried && eligible(r) && !tried_except.contains(*a))
+                    .max_by_key(|(_, r)| r.last_seen)
+            })
+            .map(|(a, _)| a.clone())
+    }
+
+    fn persist(&self, addr: &NetAddress) {
+        if let Some(record) = self.peers.get(addr) {
+            let _ = self.chaindb.write().unwrap().store_address(
+                addr.clone(),
+                record.last_seen,
+                record.last_success,
+            );
+        }
+    }
+
+    /// Register as a dispatcher listener: every `addrv2` entry gossiped
+    /// by a peer that we know how to dial is recorded as a candidate,
+    /// including `.onion` peers, which a plain `SocketAddr` could never
+    /// represent
+    pub fn listen(
+        address_manager: Arc<Mutex<AddressManager>>,
+    ) -> PeerMessageSender<PeerMessage<NetworkMessage>> {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(100);
+        std::thread::Builder::new()
+            .name("addrman".to_string())
+            .spawn(move || loop {
+                match receiver.recv() {
+                    Ok(PeerMessage::Message(_, NetworkMessage::AddrV2(addrs))) => {
+                        let mut manager = address_manager.lock().unwrap();
+                        for entry in addrs {
+                            if let Some(addr) = from_addr_v2(&entry.addr, entry.port) {
+                                manager.add_gossiped(addr);
+                            }
+                        }
+                    }
+                    Ok(PeerMessage::Message(_, NetworkMessage::Addr(addrs))) => {
+                        let mut manager = address_manager.lock().unwrap();
+                        for (_, address) in addrs {
+                            if let Some(addr) = from_addr(&address) {
+                                manager.add_gossiped(addr);
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            })
+            .expect("can not start address manager thread");
+        PeerMessageSender::new(sender)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_with_repeated_failures() {
+        let mut record = PeerRecord::new(0);
+        record.last_failure = Some(0);
+        record.failures = 0;
+        let first = record.backoff_until().unwrap();
+        record.failures = 5;
+        let later = record.backoff_until().unwrap();
+        assert!(later > first);
+    }
+}
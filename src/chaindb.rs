@@ -0,0 +1,93 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # ChainDB
+//!
+//! Storage for everything the stack keeps: block headers, the BIP157
+//! filter-header chain, and the address manager's "new"/"tried" tables.
+//! The implementation in this crate is `Hammersbald`, which holds all of
+//! it in memory.
+//!
+
+use crate::error::Error;
+use crate::proxy::NetAddress;
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::hashes::sha256d;
+use std::sync::{Arc, RwLock};
+
+pub type SharedChainDB = Arc<RwLock<dyn ChainDB>>;
+
+/// The tip of the header chain we trust
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeaderTip {
+    pub hash: BlockHash,
+    pub height: u32,
+}
+
+impl HeaderTip {
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+/// Everything the rest of the stack persists
+pub trait ChainDB: Send + Sync {
+    /// Open/initialize the store, creating it if needed
+    fn init(&mut self) -> Result<(), Error>;
+
+    /// Current best header we know of
+    fn header_tip(&self) -> Option<HeaderTip>;
+    /// Record a new best header
+    fn set_header_tip(&mut self, tip: HeaderTip);
+    /// A header's height, if we have it, so inbound `getheaders` can
+    /// locate where a peer's locator falls in our chain
+    fn header_height(&self, hash: &BlockHash) -> Option<u32>;
+    /// The hash of the header at `height` on the chain we trust, so a
+    /// `cfcheckpt` response (indexed by height) can be checked against
+    /// the filter headers we derived ourselves
+    fn header_at_height(&self, height: u32) -> Option<BlockHash>;
+    /// The full header content for `block_hash`, so inbound `getheaders`
+    /// can be served from what we actually have rather than an empty
+    /// reply
+    fn header(&self, block_hash: &BlockHash) -> Option<BlockHeader>;
+    /// Record a block's header content, keyed by its own hash
+    fn store_header(&mut self, header: BlockHeader);
+
+    /// `cfheader_i`, indexed by block hash, so the chain can be walked
+    /// and continued from any point
+    fn filter_header(&self, block_hash: &BlockHash) -> Option<sha256d::Hash>;
+    /// Record the filter header for `block_hash`, chaining to whatever
+    /// came before it
+    fn store_filter_header(&mut self, block_hash: BlockHash, cfheader: sha256d::Hash);
+    /// The raw basic filter for `block_hash`, for serving `cfilter` or
+    /// for local matching against our own watched scripts
+    fn filter(&self, block_hash: &BlockHash) -> Option<Vec<u8>>;
+    /// Record the raw basic filter for `block_hash`
+    fn store_filter(&mut self, block_hash: BlockHash, filter: Vec<u8>);
+
+    /// Addresses the address manager has learned, for seeding it across
+    /// restarts without going back to DNS seeds
+    fn read_addresses(&self) -> Result<Vec<(NetAddress, u64, Option<u64>)>, Error>;
+    /// Record (or update) a learned address with its last-seen and, if
+    /// ever connected successfully, last-success timestamps
+    fn store_address(
+        &mut self,
+        addr: NetAddress,
+        last_seen: u64,
+        last_success: Option<u64>,
+    ) -> Result<(), Error>;
+}
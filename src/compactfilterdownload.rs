@@ -0,0 +1,566 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Download and verify BIP157/158 compact block filters
+//!
+//! Runs alongside header download: once a header is known, fetches its
+//! filter header from a peer and checks it chains to the previous filter
+//! header. Basic filters are not requested from peers here; any `cfilter`
+//! a peer sends is checked against the stored filter header and tested
+//! against the scripts wallet code watches, without trusting the peer
+//! that served it.
+//!
+
+use crate::chaindb::SharedChainDB;
+use crate::dispatcher::PeerMessage;
+use crate::downstream::SharedDownstream;
+use crate::p2p::{encode_message, P2PControl, PeerId, PeerMessageSender};
+use crate::timeout::{ExpectedReply, SharedTimeout};
+use bitcoin::hash_types::BlockHash;
+use bitcoin::hashes::{sha256d, siphash24, Hash};
+use bitcoin::network::constants::Network;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_filter::{
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
+use std::sync::{Arc, Mutex};
+
+/// Golomb-Rice parameter used by BIP158 basic filters
+const P: u8 = 19;
+/// false positive rate, 1/M
+const M: u64 = 784931;
+/// every this many filter headers we exchange a checkpoint, so a lying
+/// peer can be caught early
+const CFCHECKPT_INTERVAL: usize = 1000;
+/// the only filter type defined by BIP158
+const BASIC_FILTER_TYPE: u8 = 0;
+
+/// Listens for filter related messages, assembles the filter header
+/// chain (verifying each new link as it arrives), downloads basic
+/// filters and hands matches to `downstream`
+pub struct CompactFilterDownload {
+    network: Network,
+    chaindb: SharedChainDB,
+    p2p: PeerMessageSender<P2PControl>,
+    timeout: SharedTimeout,
+    downstream: SharedDownstream,
+    /// scripts we scan every filter for; a hit means we ask for the
+    /// full block via `downstream`
+    watched: Mutex<Vec<Vec<u8>>>,
+}
+
+impl CompactFilterDownload {
+    pub fn new(
+        network: Network,
+        chaindb: SharedChainDB,
+        p2p: PeerMessageSender<P2PControl>,
+        timeout: SharedTimeout,
+        downstream: SharedDownstream,
+    ) -> (
+        PeerMessageSender<PeerMessage<NetworkMessage>>,
+        Arc<CompactFilterDownload>,
+    ) {
+        let filter_download = Arc::new(CompactFilterDownload {
+            network,
+            chaindb,
+            p2p,
+            timeout,
+            downstream,
+            watched: Mutex::new(Vec::new()),
+        });
+        let (sender, receiver) = std::sync::mpsc::sync_channel(100);
+        let worker = filter_download.clone();
+        std::thread::Builder::new()
+            .name("compactfilterdownload".to_string())
+            .spawn(move || loop {
+                match receiver.recv() {
+                    Ok(msg) => worker.process(msg),
+                    Err(_) => break,
+                }
+            })
+            .expect("can not start compact filter download thread");
+        (PeerMessageSender::new(sender), filter_download)
+    }
+
+    /// Register a script we want to be told about when a block's filter
+    /// matches it
+    pub fn watch(&self, script: Vec<u8>) {
+        self.watched.lock().unwrap().push(script);
+    }
+
+    fn process(&self, msg: PeerMessage<NetworkMessage>) {
+        match msg {
+            // a peer just connected: kick off the filter-header chain
+            // and a checkpoint sanity check against it
+            PeerMessage::Connected(peer) => {
+                if let Some(tip) = self.chaindb.read().unwrap().header_tip() {
+                    self.request_checkpoints(peer, tip.hash);
+                    self.request_headers(peer, tip.hash);
+                }
+            }
+            PeerMessage::Message(peer, message) => match message {
+                NetworkMessage::CFHeaders(cfheaders) => self.handle_cfheaders(peer, cfheaders),
+                NetworkMessage::CFilter(cfilter) => self.handle_cfilter(peer, cfilter),
+                NetworkMessage::CFCheckpt(cfcheckpt) => self.handle_cfcheckpt(peer, cfcheckpt),
+                _ => {}
+            },
+            PeerMessage::Disconnected(_) => {}
+        }
+    }
+
+    /// Ask a newly connected peer for the filter header chain from
+    /// height 0 up to `stop_hash`, our header tip
+    pub fn request_headers(&self, peer: PeerId, stop_hash: BlockHash) {
+        self.p2p.send(P2PControl::Send(
+            peer,
+            encode_message(
+                self.network,
+                NetworkMessage::GetCFHeaders(GetCFHeaders {
+                    filter_type: BASIC_FILTER_TYPE,
+                    start_height: 0,
+                    stop_hash,
+                }),
+            ),
+        ));
+        self.timeout
+            .lock()
+            .unwrap()
+            .expect(peer, ExpectedReply::CFHeaders);
+    }
+
+    /// Ask a peer for checkpoints so a lying peer is caught by comparing
+    /// them against the filter headers we derived ourselves; download is
+    /// not fanned out across peers
+    pub fn request_checkpoints(&self, peer: PeerId, stop_hash: BlockHash) {
+        self.p2p.send(P2PControl::Send(
+            peer,
+            encode_message(
+                self.network,
+                NetworkMessage::GetCFCheckpt(GetCFCheckpt {
+                    filter_type: BASIC_FILTER_TYPE,
+                    stop_hash,
+                }),
+            ),
+        ));
+        self.timeout
+            .lock()
+            .unwrap()
+            .expect(peer, ExpectedReply::CFCheckpt);
+    }
+
+    fn handle_cfheaders(&self, peer: PeerId, cfheaders: CFHeaders) {
+        self.timeout
+            .lock()
+            .unwrap()
+            .received(peer, ExpectedReply::CFHeaders);
+
+        let mut chaindb = self.chaindb.write().unwrap();
+        // the batch is keyed by its own block hashes, not by
+        // `stop_hash`: walk back from the stop block to work out which
+        // height each `filter_hashes` entry belongs to
+        let stop_height = match chaindb.header_height(&cfheaders.stop_hash) {
+            Some(height) => height,
+            None => return,
+        };
+        let n = cfheaders.filter_hashes.len() as u32;
+        if n == 0 || n > stop_height + 1 {
+            return;
+        }
+        let start_height = stop_height + 1 - n;
+
+        let mut previous = to_hash(cfheaders.previous_filter_header);
+        // the batch must continue the filter-header chain we already
+        // hold; below height 0 that is the all-zero header
+        let ours = match start_height.checked_sub(1) {
+            None => Some(sha256d::Hash::default()),
+            Some(height) => chaindb
+                .header_at_height(height)
+                .and_then(|hash| chaindb.filter_header(&hash)),
+        };
+        if ours.map_or(false, |ours| ours != previous) {
+            return;
+        }
+        for (i, filter_hash) in cfheaders.filter_hashes.into_iter().enumerate() {
+            let block_hash = match chaindb.header_at_height(start_height + i as u32) {
+                Some(hash) => hash,
+                None => break,
+            };
+            let header = next_filter_header(&to_hash(filter_hash), &previous);
+            chaindb.store_filter_header(block_hash, header);
+            previous = header;
+        }
+    }
+
+    fn handle_cfilter(&self, peer: PeerId, cfilter: CFilter) {
+        self.timeout
+            .lock()
+            .unwrap()
+            .received(peer, ExpectedReply::CFilter);
+
+        let filter = BasicFilter::from_bytes(cfilter.filter.clone());
+        {
+            let mut chaindb = self.chaindb.write().unwrap();
+            // a peer handing us a filter that does not chain to the
+            // header we already trust is lying; do not serve or match
+            // it. The stored value is the *chained* cfheader, not the
+            // filter hash, so recompute the chain link from this
+            // filter's hash and the previous block's cfheader before
+            // comparing.
+            if let Some(expected) = chaindb.filter_header(&cfilter.block_hash) {
+                let previous = chaindb
+                    .header_height(&cfilter.block_hash)
+                    .and_then(|height| height.checked_sub(1))
+                    .and_then(|height| chaindb.header_at_height(height))
+                    .and_then(|hash| chaindb.filter_header(&hash))
+                    .unwrap_or_default();
+                if next_filter_header(&sha256d::Hash::hash(&cfilter.filter), &previous) != expected {
+                    return;
+                }
+            }
+            chaindb.store_filter(cfilter.block_hash, cfilter.filter);
+        }
+
+        let watched = self.watched.lock().unwrap();
+        if !watched.is_empty() && filter.match_any(&cfilter.block_hash, &watched) {
+            self.downstream
+                .lock()
+                .unwrap()
+                .filter_matched(&cfilter.block_hash);
+        }
+    }
+
+    fn handle_cfcheckpt(&self, peer: PeerId, cfcheckpt: CFCheckpt) {
+        self.timeout
+            .lock()
+            .unwrap()
+            .received(peer, ExpectedReply::CFCheckpt);
+
+        let ours = {
+            let chaindb = self.chaindb.read().unwrap();
+            cfcheckpt
+                .filter_headers
+                .iter()
+                .enumerate()
+                .map(|(i, _)| {
+                    let height = (i as u32 + 1) * CFCHECKPT_INTERVAL as u32;
+                    chaindb
+                        .header_at_height(height)
+                        .and_then(|hash| chaindb.filter_header(&hash))
+                })
+                .collect::<Option<Vec<_>>>()
+        };
+
+        let theirs = cfcheckpt
+            .filter_headers
+            .into_iter()
+            .map(to_hash)
+            .collect::<Vec<_>>();
+
+        // if we don't have every checkpoint height ourselves yet there
+        // is nothing to compare against; a lying peer is only caught
+        // once our own chain has caught up far enough
+        if let Some(ours) = ours {
+            if !verify_checkpoints(&theirs, &ours) {
+                self.p2p.send(P2PControl::Disconnect(peer));
+            }
+        }
+    }
+}
+
+fn to_hash(header: bitcoin::hash_types::FilterHash) -> sha256d::Hash {
+    sha256d::Hash::from_inner(header.into_inner())
+}
+
+/// Decode a Bitcoin CompactSize prefix, returning the value and how
+/// many bytes it occupied. BIP158 filters can carry thousands of
+/// elements, well past what a single byte can express.
+fn read_compact_size(raw: &[u8]) -> (u64, usize) {
+    match raw.first() {
+        None => (0, 0),
+        Some(0xff) if raw.len() >= 9 => (u64::from_le_bytes(raw[1..9].try_into().unwrap()), 9),
+        Some(0xfe) if raw.len() >= 5 => {
+            (u32::from_le_bytes(raw[1..5].try_into().unwrap()) as u64, 5)
+        }
+        Some(0xfd) if raw.len() >= 3 => {
+            (u16::from_le_bytes(raw[1..3].try_into().unwrap()) as u64, 3)
+        }
+        // truncated multi-byte prefix: nothing sane to decode
+        Some(0xff) | Some(0xfe) | Some(0xfd) => (0, raw.len()),
+        Some(&small) => (small as u64, 1),
+    }
+}
+
+/// One Golomb-Rice coded basic filter as defined by BIP158
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicFilter {
+    n_elements: u64,
+    content: Vec<u8>,
+}
+
+impl BasicFilter {
+    /// Build a filter for a block: `elements` is every output scriptPubKey
+    /// created in the block plus every prevout scriptPubKey spent by it.
+    pub fn build(block_hash: &BlockHash, elements: &[Vec<u8>]) -> BasicFilter {
+        let n_elements = elements.len() as u64;
+        let key = siphash_key(block_hash);
+        let mut hashes = elements
+            .iter()
+            .map(|e| hash_to_range(&key, e, n_elements.max(1) * M))
+            .collect::<Vec<_>>();
+        hashes.sort_unstable();
+
+        let mut writer = BitWriter::new();
+        let mut last = 0u64;
+        for h in hashes {
+            golomb_rice_encode(&mut writer, h - last, P);
+            last = h;
+        }
+        BasicFilter {
+            n_elements,
+            content: writer.finish(),
+        }
+    }
+
+    /// Wrap raw BIP158 filter bytes downloaded from a peer, together
+    /// with the element count encoded as a compact-size prefix
+    pub fn from_bytes(raw: Vec<u8>) -> BasicFilter {
+        let (n_elements, prefix_len) = read_compact_size(&raw);
+        let content = raw.into_iter().skip(prefix_len).collect();
+        BasicFilter { n_elements, content }
+    }
+
+    /// True if every element in `query` is a member of this filter
+    pub fn match_all(&self, block_hash: &BlockHash, query: &[Vec<u8>]) -> bool {
+        if query.is_empty() {
+            return true;
+        }
+        let key = siphash_key(block_hash);
+        let mut targets = query
+            .iter()
+            .map(|e| hash_to_range(&key, e, self.n_elements.max(1) * M))
+            .collect::<Vec<_>>();
+        targets.sort_unstable();
+        targets.dedup();
+
+        let mut reader = BitReader::new(&self.content);
+        let mut value = 0u64;
+        let mut ti = 0usize;
+        for _ in 0..self.n_elements {
+            value += golomb_rice_decode(&mut reader, P);
+            if ti < targets.len() && targets[ti] < value {
+                return false;
+            }
+            if ti < targets.len() && targets[ti] == value {
+                ti += 1;
+                if ti == targets.len() {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// True if any element in `query` is a member of this filter — the
+    /// check a wallet actually wants: "does this block possibly concern
+    /// any script of mine?"
+    pub fn match_any(&self, block_hash: &BlockHash, query: &[Vec<u8>]) -> bool {
+        if query.is_empty() {
+            return false;
+        }
+        let key = siphash_key(block_hash);
+        let mut targets = query
+            .iter()
+            .map(|e| hash_to_range(&key, e, self.n_elements.max(1) * M))
+            .collect::<Vec<_>>();
+        targets.sort_unstable();
+        targets.dedup();
+
+        let mut reader = BitReader::new(&self.content);
+        let mut value = 0u64;
+        let mut ti = 0usize;
+        for _ in 0..self.n_elements {
+            value += golomb_rice_decode(&mut reader, P);
+            while ti < targets.len() && targets[ti] < value {
+                ti += 1;
+            }
+            if ti < targets.len() && targets[ti] == value {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn hash(&self) -> sha256d::Hash {
+        sha256d::Hash::hash(&self.content)
+    }
+}
+
+/// `cfheader_i = double_sha256(filter_hash_i || cfheader_{i-1})`
+pub fn next_filter_header(filter_hash: &sha256d::Hash, previous: &sha256d::Hash) -> sha256d::Hash {
+    let mut buf = Vec::with_capacity(64);
+    buf.extend_from_slice(&filter_hash[..]);
+    buf.extend_from_slice(&previous[..]);
+    sha256d::Hash::hash(&buf)
+}
+
+/// Verify a peer's `cfcheckpt` response: `checkpoints` and `ours` are
+/// both already reduced to one filter header per `CFCHECKPT_INTERVAL`th
+/// height, so a lying peer is caught by straight equality
+pub fn verify_checkpoints(checkpoints: &[sha256d::Hash], ours: &[sha256d::Hash]) -> bool {
+    checkpoints == ours
+}
+
+fn siphash_key(block_hash: &BlockHash) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&block_hash[0..16]);
+    key
+}
+
+fn hash_to_range(key: &[u8; 16], element: &[u8], range: u64) -> u64 {
+    let h = siphash24::Hash::hash_to_u64_with_keys(
+        u64::from_le_bytes(key[0..8].try_into().unwrap()),
+        u64::from_le_bytes(key[8..16].try_into().unwrap()),
+        element,
+    );
+    // 128-bit multiply-and-shift reduction into [0, range)
+    (((h as u128) * (range as u128)) >> 64) as u64
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit: u8,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        // no byte allocated yet: `bit == 8` is the sentinel that tells
+        // `push` to start one. A filter with zero elements then
+        // serializes to an empty byte vector instead of a spurious
+        // leading 0x00.
+        BitWriter {
+            bytes: Vec::new(),
+            bit: 8,
+        }
+    }
+
+    fn push(&mut self, bit: bool) {
+        if self.bit == 8 {
+            self.bytes.push(0);
+            self.bit = 0;
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 1 << (7 - self.bit);
+        }
+        self.bit += 1;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    byte: usize,
+    bit: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            bytes,
+            byte: 0,
+            bit: 0,
+        }
+    }
+
+    fn pop(&mut self) -> bool {
+        let b = self.bytes[self.byte] & (1 << (7 - self.bit)) != 0;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.byte += 1;
+        }
+        b
+    }
+}
+
+fn golomb_rice_encode(writer: &mut BitWriter, value: u64, p: u8) {
+    let quotient = value >> p;
+    for _ in 0..quotient {
+        writer.push(true);
+    }
+    writer.push(false);
+    for i in (0..p).rev() {
+        writer.push((value >> i) & 1 == 1);
+    }
+}
+
+fn golomb_rice_decode(reader: &mut BitReader, p: u8) -> u64 {
+    let mut quotient = 0u64;
+    while reader.pop() {
+        quotient += 1;
+    }
+    let mut remainder = 0u64;
+    for _ in 0..p {
+        remainder = (remainder << 1) | reader.pop() as u64;
+    }
+    (quotient << p) | remainder
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip_filter_matches_its_own_elements() {
+        let block_hash = BlockHash::default();
+        let elements = vec![b"foo".to_vec(), b"bar".to_vec(), b"baz".to_vec()];
+        let filter = BasicFilter::build(&block_hash, &elements);
+        assert!(filter.match_all(&block_hash, &[b"foo".to_vec()]));
+        assert!(filter.match_all(&block_hash, &[b"bar".to_vec(), b"baz".to_vec()]));
+        assert!(filter.match_any(&block_hash, &[b"nope".to_vec(), b"baz".to_vec()]));
+        assert!(!filter.match_any(&block_hash, &[b"nope".to_vec()]));
+    }
+
+    #[test]
+    fn empty_filter_serializes_to_empty_bytes() {
+        let block_hash = BlockHash::default();
+        let filter = BasicFilter::build(&block_hash, &[]);
+        assert!(filter.content.is_empty());
+    }
+
+    #[test]
+    fn filter_header_chains() {
+        let genesis = sha256d::Hash::hash(b"genesis");
+        let filter_hash = sha256d::Hash::hash(b"filter");
+        let header = next_filter_header(&filter_hash, &genesis);
+        assert_ne!(header, genesis);
+        assert_eq!(header, next_filter_header(&filter_hash, &genesis));
+    }
+
+    #[test]
+    fn verify_checkpoints_compares_height_aligned_headers() {
+        let mut chain = vec![sha256d::Hash::hash(b"genesis")];
+        for i in 1..=2000 {
+            let prev = *chain.last().unwrap();
+            chain.push(next_filter_header(&sha256d::Hash::hash(&i.to_le_bytes()), &prev));
+        }
+        let ours = vec![chain[1000], chain[2000]];
+        assert!(verify_checkpoints(&ours, &ours));
+        assert!(!verify_checkpoints(&vec![chain[999], chain[2000]], &ours));
+    }
+}
@@ -19,7 +19,10 @@
 //! Assembles modules of this library to a complete service
 //!
 
+use crate::addrman::AddressManager;
 use crate::chaindb::SharedChainDB;
+use crate::compactfilterdownload::CompactFilterDownload;
+use crate::control::ConstructorControl;
 use crate::dispatcher::Dispatcher;
 use crate::dns::dns_seed;
 use crate::downstream::{DownStreamDummy, SharedDownstream};
@@ -29,6 +32,8 @@ use crate::headerdownload::HeaderDownload;
 use crate::p2p::BitcoinP2PConfig;
 use crate::p2p::{P2PControl, PeerMessageSender, PeerSource, P2P};
 use crate::ping::Ping;
+use crate::proxy::{NetAddress, ProxyConfig};
+use crate::server::{wants_filter_server, FilterServer, HeaderServer};
 use crate::timeout::Timeout;
 use bitcoin::network::constants::{Network, ServiceFlags};
 use bitcoin::network::message::NetworkMessage;
@@ -56,6 +61,9 @@ const USER_AGENT: &'static str = concat!("/Murmel:", env!("CARGO_PKG_VERSION"),
 /// The complete stack
 pub struct Constructor {
     p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
+    address_manager: Arc<Mutex<AddressManager>>,
+    compact_filters: Arc<CompactFilterDownload>,
+    proxy: Option<ProxyConfig>,
     /// this should be accessed by Lightning
     pub downstream: SharedDownstream,
 }
@@ -78,12 +86,28 @@ impl Constructor {
         Ok(Arc::new(RwLock::new(chaindb)))
     }
 
-    /// Construct the stack
+    /// Construct the stack. Returns the stack itself together with a
+    /// control handle that embedders can use to list connected peers,
+    /// connect/disconnect specific addresses and query sync progress
+    /// while `Constructor::run` is driving the executor loop.
+    ///
+    /// `proxy`, if set, routes outbound connections to `.onion` peers
+    /// through a SOCKS5 endpoint, e.g. a local Tor daemon. Clearnet peers
+    /// are always dialed directly.
+    ///
+    /// `services` is what we serve to inbound peers, e.g.
+    /// `ServiceFlags::NETWORK_LIMITED`, plus `ServiceFlags::COMPACT_FILTERS`
+    /// once the caller wants to serve BIP157 filters. No `version` message
+    /// is sent, so these flags are not announced to peers. This is independent
+    /// of which services we require of *outbound* peers, which
+    /// `Constructor::run` takes separately.
     pub fn new(
         network: Network,
         listen: Vec<SocketAddr>,
         chaindb: SharedChainDB,
-    ) -> Result<Constructor, Error> {
+        proxy: Option<ProxyConfig>,
+        services: ServiceFlags,
+    ) -> Result<(Constructor, Arc<ConstructorControl>), Error> {
         const BACK_PRESSURE: usize = 10;
 
         let (to_dispatcher, from_p2p) = mpsc::sync_channel(BACK_PRESSURE);
@@ -95,6 +119,8 @@ impl Constructor {
             user_agent: USER_AGENT.to_owned(),
             height: AtomicUsize::new(0),
             server: !listen.is_empty(),
+            proxy,
+            services,
         };
 
         let (p2p, p2p_control) = P2P::new(
@@ -107,33 +133,107 @@ impl Constructor {
 
         let timeout = Arc::new(Mutex::new(Timeout::new(p2p_control.clone())));
 
+        let address_manager = Arc::new(Mutex::new(AddressManager::new(chaindb.clone())));
+
         let mut dispatcher = Dispatcher::new(from_p2p);
 
+        dispatcher.add_listener(AddressManager::listen(address_manager.clone()));
+
         dispatcher.add_listener(HeaderDownload::new(
+            network,
             chaindb.clone(),
             p2p_control.clone(),
             timeout.clone(),
             downstream.clone(),
         ));
-        dispatcher.add_listener(Ping::new(p2p_control.clone(), timeout.clone()));
+        // BIP157/158: fetch and verify filter headers alongside block
+        // headers, and basic filters on demand, so Lightning/wallet code
+        // can scan for relevant scripts without trusting a server.
+        let (compact_filter_listener, compact_filters) = CompactFilterDownload::new(
+            network,
+            chaindb.clone(),
+            p2p_control.clone(),
+            timeout.clone(),
+            downstream.clone(),
+        );
+        dispatcher.add_listener(compact_filter_listener);
+        dispatcher.add_listener(Ping::new(network, p2p_control.clone(), timeout.clone()));
+
+        // disconnect peers that leave an expected reply unanswered
+        let overdue = timeout.clone();
+        std::thread::Builder::new()
+            .name("timeout".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(Duration::from_secs(10));
+                overdue.lock().unwrap().check();
+            })
+            .expect("can not start timeout thread");
+
+        if !listen.is_empty() {
+            // Advertise ourselves as a useful upstream rather than a
+            // silent leech: serve headers to anyone, and filters too
+            // once we advertise NODE_COMPACT_FILTERS.
+            dispatcher.add_listener(HeaderServer::new(
+                network,
+                chaindb.clone(),
+                p2p_control.clone(),
+            ));
+            if wants_filter_server(services) {
+                dispatcher.add_listener(FilterServer::new(
+                    network,
+                    chaindb.clone(),
+                    p2p_control.clone(),
+                ));
+            }
+        }
+
+        // start fanning out inbound messages now that every listener is
+        // registered; nothing must be added after this point
+        dispatcher.run();
 
         for addr in &listen {
             p2p_control.send(P2PControl::Bind(addr.clone()));
         }
 
-        Ok(Constructor { p2p, downstream })
+        let control = Arc::new(ConstructorControl::new(
+            p2p.clone(),
+            p2p_control.clone(),
+            chaindb.clone(),
+        ));
+
+        Ok((
+            Constructor {
+                p2p,
+                address_manager,
+                compact_filters,
+                proxy,
+                downstream,
+            },
+            control,
+        ))
+    }
+
+    /// Register a script to be notified about via `downstream` when a
+    /// peer's compact filter matches it
+    pub fn watch(&self, script: Vec<u8>) {
+        self.compact_filters.watch(script);
     }
 
     /// Run the stack. This should be called AFTER registering listener of the ChainWatchInterface,
     /// so they are called as the stack catches up with the blockchain
-    /// * peers - connect to these peers at startup (might be empty)
+    /// * peers - connect to these peers at startup (might be empty); `.onion` addresses are
+    /// dialed through the proxy configured in `Constructor::new`, if any
     /// * min_connections - keep connections with at least this number of peers. Peers will be randomly chosen
     /// from those discovered in earlier runs
+    /// * required_services - outbound peers not advertising at least these services are
+    /// pruned by `poll_events`; this is independent of the services we ourselves serve,
+    /// set via `Constructor::new`
     pub fn run(
         &mut self,
         network: Network,
-        peers: Vec<SocketAddr>,
+        peers: Vec<NetAddress>,
         min_connections: usize,
+        required_services: ServiceFlags,
     ) -> Result<(), Error> {
         let mut executor = ThreadPoolBuilder::new()
             .name_prefix("bitcoin-connect")
@@ -143,19 +243,26 @@ impl Constructor {
 
         let p2p = self.p2p.clone();
         for addr in &peers {
-            executor
-                .spawn(
-                    p2p.add_peer("bitcoin", PeerSource::Outgoing(addr.clone()))
-                        .map(|_| ()),
-                )
-                .expect("can not spawn task for peers");
+            if let Some(source) = peer_source(addr, &self.proxy) {
+                executor
+                    .spawn(p2p.add_peer("bitcoin", source).map(|_| ()))
+                    .expect("can not spawn task for peers");
+            }
         }
 
         let keep_connected = KeepConnected {
             min_connections,
             p2p: self.p2p.clone(),
             earlier: HashSet::new(),
-            dns: dns_seed(network),
+            dns: dns_seed(network)
+                .into_iter()
+                .map(|addr| match addr {
+                    SocketAddr::V4(v4) => NetAddress::IPv4(*v4.ip(), v4.port()),
+                    SocketAddr::V6(v6) => NetAddress::IPv6(*v6.ip(), v6.port()),
+                })
+                .collect(),
+            address_manager: self.address_manager.clone(),
+            proxy: self.proxy.clone(),
             cex: executor.clone(),
         };
         executor
@@ -165,19 +272,33 @@ impl Constructor {
         let p2p = self.p2p.clone();
         let mut cex = executor.clone();
         executor.run(future::poll_fn(move |_| {
-            let needed_services = ServiceFlags::NONE;
-            p2p.poll_events("bitcoin", needed_services, &mut cex);
+            p2p.poll_events("bitcoin", required_services, &mut cex);
             Async::Ready(())
         }));
         Ok(())
     }
 }
 
+/// Pick how to dial a `NetAddress`: clearnet addresses connect directly,
+/// `.onion` addresses go through the configured SOCKS5 proxy so Tor
+/// peers are reachable and we can relay over the network. Returns
+/// `None` for a `.onion` address when no proxy is configured, since
+/// there is no way to reach it.
+fn peer_source(addr: &NetAddress, proxy: &Option<ProxyConfig>) -> Option<PeerSource> {
+    match (addr.socket_addr(), proxy) {
+        (Some(socket_addr), _) => Some(PeerSource::Outgoing(socket_addr)),
+        (None, Some(proxy)) => Some(PeerSource::Proxy(addr.clone(), proxy.clone())),
+        (None, None) => None,
+    }
+}
+
 #[derive(Clone)]
 struct KeepConnected {
     cex: ThreadPool,
-    dns: Vec<SocketAddr>,
-    earlier: HashSet<SocketAddr>,
+    dns: Vec<NetAddress>,
+    earlier: HashSet<NetAddress>,
+    address_manager: Arc<Mutex<AddressManager>>,
+    proxy: Option<ProxyConfig>,
     p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
     min_connections: usize,
 }
@@ -187,23 +308,50 @@ impl Future for KeepConnected {
 
     fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Async<Self::Output> {
         if self.p2p.n_connected_peers() < self.min_connections {
-            let eligible = self
-                .dns
-                .iter()
-                .cloned()
-                .filter(|a| !self.earlier.contains(a))
-                .collect::<Vec<_>>();
-            if eligible.len() > 0 {
-                let mut rng = thread_rng();
-                let choice = eligible[(rng.next_u32() as usize) % eligible.len()];
-                self.earlier.insert(choice.clone());
-                let add = self
-                    .p2p
-                    .add_peer("bitcoin", PeerSource::Outgoing(choice))
-                    .map(|_| ());
-                self.cex
-                    .spawn(add)
-                    .expect("can not add peer for outgoing connection");
+            // Prefer what we already learned about the network over DNS:
+            // the address manager favors "tried" addresses with a recent
+            // success and skips anything still in backoff after a failed
+            // connect. Only fall back to DNS seeds when it has nothing to
+            // offer, e.g. on a brand new chaindb.
+            let candidate = self
+                .address_manager
+                .lock()
+                .unwrap()
+                .candidate(&self.earlier);
+
+            let choice = if let Some(addr) = candidate {
+                Some(addr)
+            } else {
+                let eligible = self
+                    .dns
+                    .iter()
+                    .cloned()
+                    .filter(|a| !self.earlier.contains(a))
+                    .collect::<Vec<_>>();
+                if eligible.is_empty() {
+                    None
+                } else {
+                    let mut rng = thread_rng();
+                    Some(eligible[(rng.next_u32() as usize) % eligible.len()].clone())
+                }
+            };
+
+            if let Some(choice) = choice {
+                if let Some(source) = peer_source(&choice, &self.proxy) {
+                    self.earlier.insert(choice.clone());
+                    let address_manager = self.address_manager.clone();
+                    let failed_address_manager = address_manager.clone();
+                    let add = self.p2p.add_peer("bitcoin", source).map(move |result| {
+                        if result.is_ok() {
+                            address_manager.lock().unwrap().mark_success(choice);
+                        } else {
+                            failed_address_manager.lock().unwrap().mark_failure(choice);
+                        }
+                    });
+                    self.cex
+                        .spawn(add)
+                        .expect("can not add peer for outgoing connection");
+                }
             }
         }
         Async::Ready(())
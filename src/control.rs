@@ -0,0 +1,105 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Runtime control of the stack
+//!
+//! A handle embedders can hold on to while `Constructor::run` is driving
+//! the executor loop, to inspect and steer the running p2p stack: list
+//! connected peers, connect to or disconnect a specific address, and
+//! check sync progress. Everything here is a thin read/write wrapper
+//! around the existing `P2PControl` channel and the chaindb tip, so a
+//! CLI or RPC front-end can be built on top without reaching into the
+//! stack's internals.
+//!
+
+use crate::chaindb::SharedChainDB;
+use crate::p2p::{P2PControl, PeerInfo, PeerMessageSender, PeerSource, P2P};
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message::RawNetworkMessage;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::p2p::BitcoinP2PConfig;
+
+/// Sync progress as observed by the local chaindb against the best peer
+/// we know of
+#[derive(Clone, Debug)]
+pub struct SyncProgress {
+    pub header_height: u32,
+    pub best_peer_height: u32,
+}
+
+/// A handle to the running stack, safe to call from any thread while
+/// `Constructor::run` is driving the executor
+pub struct ConstructorControl {
+    p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
+    p2p_control: PeerMessageSender<P2PControl>,
+    chaindb: SharedChainDB,
+}
+
+impl ConstructorControl {
+    pub(crate) fn new(
+        p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
+        p2p_control: PeerMessageSender<P2PControl>,
+        chaindb: SharedChainDB,
+    ) -> ConstructorControl {
+        ConstructorControl {
+            p2p,
+            p2p_control,
+            chaindb,
+        }
+    }
+
+    /// Currently connected peers with their user-agent/height/services
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.p2p.peers()
+    }
+
+    /// Connect to a specific peer, outside the normal `KeepConnected` loop
+    pub fn connect(&self, addr: SocketAddr) {
+        self.p2p_control
+            .send(P2PControl::Connect(PeerSource::Outgoing(addr)));
+    }
+
+    /// Disconnect a specific peer, if it is currently connected
+    pub fn disconnect(&self, addr: SocketAddr) {
+        if let Some(peer) = self.p2p.peers().iter().find(|p| p.address == Some(addr)) {
+            self.p2p_control.send(P2PControl::Disconnect(peer.id));
+        }
+    }
+
+    /// Current header height vs. the best height reported by a connected peer
+    pub fn sync_progress(&self) -> SyncProgress {
+        let header_height = self
+            .chaindb
+            .read()
+            .unwrap()
+            .header_tip()
+            .map(|tip| tip.height())
+            .unwrap_or(0);
+        let best_peer_height = self
+            .p2p
+            .peers()
+            .iter()
+            .map(|p| p.height)
+            .max()
+            .unwrap_or(0);
+        SyncProgress {
+            header_height,
+            best_peer_height,
+        }
+    }
+}
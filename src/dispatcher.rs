@@ -0,0 +1,71 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Dispatcher
+//!
+//! Fans every message read off the wire out to whichever listeners
+//! registered interest (`HeaderDownload`, `CompactFilterDownload`,
+//! `Ping`, the inbound servers, ...). Each listener is just a
+//! `PeerMessageSender` feeding its own thread through a bounded
+//! channel; while one listener's channel is full, delivery to every
+//! listener waits for it to drain.
+//!
+
+use crate::p2p::{PeerId, PeerMessageSender};
+use std::sync::mpsc::Receiver;
+
+/// One event read off (or destined for) the wire
+#[derive(Clone)]
+pub enum PeerMessage<Message> {
+    Connected(PeerId),
+    Disconnected(PeerId),
+    Message(PeerId, Message),
+}
+
+pub struct Dispatcher<Message> {
+    receiver: Receiver<PeerMessage<Message>>,
+    listeners: Vec<PeerMessageSender<PeerMessage<Message>>>,
+}
+
+impl<Message: Clone + Send + 'static> Dispatcher<Message> {
+    pub fn new(receiver: Receiver<PeerMessage<Message>>) -> Dispatcher<Message> {
+        Dispatcher {
+            receiver,
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Register a listener to receive every event from now on
+    pub fn add_listener(&mut self, listener: PeerMessageSender<PeerMessage<Message>>) {
+        self.listeners.push(listener);
+    }
+
+    /// Start fanning out events to the registered listeners. Consumes
+    /// the dispatcher, since nothing can be registered after the loop
+    /// starts reading from `receiver`.
+    pub fn run(self) {
+        std::thread::Builder::new()
+            .name("dispatcher".to_string())
+            .spawn(move || {
+                while let Ok(msg) = self.receiver.recv() {
+                    for listener in &self.listeners {
+                        listener.send(msg.clone());
+                    }
+                }
+            })
+            .expect("can not start dispatcher thread");
+    }
+}
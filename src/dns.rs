@@ -0,0 +1,56 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # DNS seeds
+//!
+//! Bootstraps `KeepConnected` with peer addresses before the address
+//! manager has learned any of its own, by resolving the well-known seed
+//! hostnames operated by the Bitcoin Core community.
+//!
+
+use bitcoin::network::constants::Network;
+use std::net::SocketAddr;
+
+const MAINNET_SEEDS: &[&str] = &[
+    "seed.bitcoin.sipa.be:8333",
+    "dnsseed.bluematt.me:8333",
+    "dnsseed.bitcoin.dashjr.org:8333",
+    "seed.bitcoinstats.com:8333",
+    "seed.btc.petertodd.org:8333",
+    "seed.bitcoin.jonasschnelli.ch:8333",
+    "seed.bitcoin.sprovoost.nl:8333",
+];
+
+const TESTNET_SEEDS: &[&str] = &[
+    "testnet-seed.bitcoin.jonasschnelli.ch:18333",
+    "seed.tbtc.petertodd.org:18333",
+    "seed.testnet.bitcoin.sprovoost.nl:18333",
+];
+
+/// Resolve the seed hostnames for `network`, skipping any that fail to
+/// resolve (e.g. no network access) rather than failing the whole call
+pub fn dns_seed(network: Network) -> Vec<SocketAddr> {
+    let seeds: &[&str] = match network {
+        Network::Bitcoin => MAINNET_SEEDS,
+        Network::Testnet => TESTNET_SEEDS,
+        _ => &[],
+    };
+    seeds
+        .iter()
+        .filter_map(|seed| std::net::ToSocketAddrs::to_socket_addrs(seed).ok())
+        .flatten()
+        .collect()
+}
@@ -0,0 +1,43 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Downstream
+//!
+//! What the rest of the stack (Lightning/wallet code) is notified of as
+//! the chain is followed.
+//!
+
+use bitcoin::hash_types::BlockHash;
+use std::sync::{Arc, Mutex};
+
+/// Notified as the chain is followed
+pub trait Downstream: Send {
+    /// A new block has connected to the chain we trust
+    fn block_connected(&mut self, hash: &BlockHash, height: u32);
+    /// A block's BIP158 filter matched one of the scripts we are watching for
+    fn filter_matched(&mut self, hash: &BlockHash);
+}
+
+pub type SharedDownstream = Arc<Mutex<dyn Downstream>>;
+
+/// A `Downstream` that drops everything, used where nobody downstream
+/// has registered interest yet
+pub struct DownStreamDummy {}
+
+impl Downstream for DownStreamDummy {
+    fn block_connected(&mut self, _hash: &BlockHash, _height: u32) {}
+    fn filter_matched(&mut self, _hash: &BlockHash) {}
+}
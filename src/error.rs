@@ -0,0 +1,49 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Errors
+//!
+
+use std::fmt;
+use std::io;
+
+/// Errors that can occur anywhere in the stack
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    ChainDB(String),
+    P2P(String),
+    Filter(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "IO error: {}", e),
+            Error::ChainDB(s) => write!(f, "chaindb error: {}", s),
+            Error::P2P(s) => write!(f, "p2p error: {}", s),
+            Error::Filter(s) => write!(f, "filter error: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::IO(e)
+    }
+}
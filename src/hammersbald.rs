@@ -0,0 +1,147 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Hammersbald backed ChainDB
+//!
+//! A `ChainDB` with one in-memory map per column (headers, filter
+//! headers, filters, addresses). `Hammersbald::new` creates the
+//! directory at its path but reads nothing from it and writes nothing
+//! to it, so nothing stored here survives a restart; `Hammersbald::mem`
+//! differs only in having no path.
+//!
+
+use crate::chaindb::{ChainDB, HeaderTip};
+use crate::error::Error;
+use crate::proxy::NetAddress;
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::hashes::sha256d;
+use bitcoin::network::constants::Network;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub struct Hammersbald {
+    path: Option<PathBuf>,
+    network: Network,
+    tip: Option<HeaderTip>,
+    header_heights: HashMap<BlockHash, u32>,
+    hashes_by_height: HashMap<u32, BlockHash>,
+    headers: HashMap<BlockHash, BlockHeader>,
+    filter_headers: HashMap<BlockHash, sha256d::Hash>,
+    filters: HashMap<BlockHash, Vec<u8>>,
+    addresses: HashMap<NetAddress, (u64, Option<u64>)>,
+}
+
+impl Hammersbald {
+    /// An empty in-memory database whose `init` creates `path`
+    pub fn new(path: &Path, network: Network) -> Result<Hammersbald, Error> {
+        Ok(Hammersbald {
+            path: Some(path.to_owned()),
+            network,
+            tip: None,
+            header_heights: HashMap::new(),
+            hashes_by_height: HashMap::new(),
+            headers: HashMap::new(),
+            filter_headers: HashMap::new(),
+            filters: HashMap::new(),
+            addresses: HashMap::new(),
+        })
+    }
+
+    /// An in-memory only database, e.g. for tests
+    pub fn mem(network: Network) -> Result<Hammersbald, Error> {
+        Ok(Hammersbald {
+            path: None,
+            network,
+            tip: None,
+            header_heights: HashMap::new(),
+            hashes_by_height: HashMap::new(),
+            headers: HashMap::new(),
+            filter_headers: HashMap::new(),
+            filters: HashMap::new(),
+            addresses: HashMap::new(),
+        })
+    }
+}
+
+impl ChainDB for Hammersbald {
+    fn init(&mut self) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            std::fs::create_dir_all(path)?;
+        }
+        Ok(())
+    }
+
+    fn header_tip(&self) -> Option<HeaderTip> {
+        self.tip
+    }
+
+    fn set_header_tip(&mut self, tip: HeaderTip) {
+        self.header_heights.insert(tip.hash, tip.height);
+        self.hashes_by_height.insert(tip.height, tip.hash);
+        self.tip = Some(tip);
+    }
+
+    fn header_height(&self, hash: &BlockHash) -> Option<u32> {
+        self.header_heights.get(hash).copied()
+    }
+
+    fn header_at_height(&self, height: u32) -> Option<BlockHash> {
+        self.hashes_by_height.get(&height).copied()
+    }
+
+    fn header(&self, block_hash: &BlockHash) -> Option<BlockHeader> {
+        self.headers.get(block_hash).copied()
+    }
+
+    fn store_header(&mut self, header: BlockHeader) {
+        self.headers.insert(header.block_hash(), header);
+    }
+
+    fn filter_header(&self, block_hash: &BlockHash) -> Option<sha256d::Hash> {
+        self.filter_headers.get(block_hash).copied()
+    }
+
+    fn store_filter_header(&mut self, block_hash: BlockHash, cfheader: sha256d::Hash) {
+        self.filter_headers.insert(block_hash, cfheader);
+    }
+
+    fn filter(&self, block_hash: &BlockHash) -> Option<Vec<u8>> {
+        self.filters.get(block_hash).cloned()
+    }
+
+    fn store_filter(&mut self, block_hash: BlockHash, filter: Vec<u8>) {
+        self.filters.insert(block_hash, filter);
+    }
+
+    fn read_addresses(&self) -> Result<Vec<(NetAddress, u64, Option<u64>)>, Error> {
+        Ok(self
+            .addresses
+            .iter()
+            .map(|(addr, (last_seen, last_success))| (addr.clone(), *last_seen, *last_success))
+            .collect())
+    }
+
+    fn store_address(
+        &mut self,
+        addr: NetAddress,
+        last_seen: u64,
+        last_success: Option<u64>,
+    ) -> Result<(), Error> {
+        self.addresses.insert(addr, (last_seen, last_success));
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Download and verify block headers
+//!
+//! Asks a newly connected peer for headers from our tip, checks each
+//! one's proof of work and that it connects to the header before it,
+//! then stores it and keeps asking for more until a reply comes back
+//! short of a full batch.
+//!
+
+use crate::chaindb::{HeaderTip, SharedChainDB};
+use crate::dispatcher::PeerMessage;
+use crate::downstream::SharedDownstream;
+use crate::p2p::{encode_message, P2PControl, PeerId, PeerMessageSender};
+use crate::timeout::{ExpectedReply, SharedTimeout};
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::network::constants::Network;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_blockdata::GetHeadersMessage;
+use std::sync::Arc;
+
+/// Protocol version field of `getheaders`; informational only, peers
+/// negotiate the actual version during the handshake
+const GETHEADERS_VERSION: u32 = 70001;
+/// Core caps a `headers` reply at this many entries; a full batch means
+/// there is probably more to fetch
+const HEADERS_PER_MESSAGE: usize = 2000;
+
+pub struct HeaderDownload {
+    network: Network,
+    chaindb: SharedChainDB,
+    p2p: PeerMessageSender<P2PControl>,
+    timeout: SharedTimeout,
+    downstream: SharedDownstream,
+}
+
+impl HeaderDownload {
+    pub fn new(
+        network: Network,
+        chaindb: SharedChainDB,
+        p2p: PeerMessageSender<P2PControl>,
+        timeout: SharedTimeout,
+        downstream: SharedDownstream,
+    ) -> PeerMessageSender<PeerMessage<NetworkMessage>> {
+        let header_download = Arc::new(HeaderDownload {
+            network,
+            chaindb,
+            p2p,
+            timeout,
+            downstream,
+        });
+        let (sender, receiver) = std::sync::mpsc::sync_channel(100);
+        std::thread::Builder::new()
+            .name("headerdownload".to_string())
+            .spawn(move || loop {
+                match receiver.recv() {
+                    Ok(msg) => header_download.process(msg),
+                    Err(_) => break,
+                }
+            })
+            .expect("can not start header download thread");
+        PeerMessageSender::new(sender)
+    }
+
+    fn process(&self, msg: PeerMessage<NetworkMessage>) {
+        match msg {
+            PeerMessage::Connected(peer) => self.ask_for_headers(peer),
+            PeerMessage::Message(peer, NetworkMessage::Headers(headers)) => {
+                self.handle_headers(peer, headers)
+            }
+            _ => {}
+        }
+    }
+
+    /// Ask `peer` for headers starting right after our current tip
+    fn ask_for_headers(&self, peer: PeerId) {
+        let locator_hashes = self
+            .chaindb
+            .read()
+            .unwrap()
+            .header_tip()
+            .map(|tip| vec![tip.hash])
+            .unwrap_or_default();
+        self.p2p.send(P2PControl::Send(
+            peer,
+            encode_message(
+                self.network,
+                NetworkMessage::GetHeaders(GetHeadersMessage {
+                    version: GETHEADERS_VERSION,
+                    locator_hashes,
+                    stop_hash: BlockHash::default(),
+                }),
+            ),
+        ));
+        self.timeout
+            .lock()
+            .unwrap()
+            .expect(peer, ExpectedReply::Headers);
+    }
+
+    fn handle_headers(&self, peer: PeerId, headers: Vec<BlockHeader>) {
+        self.timeout
+            .lock()
+            .unwrap()
+            .received(peer, ExpectedReply::Headers);
+
+        if headers.is_empty() {
+            return;
+        }
+
+        let received = headers.len();
+        let mut chaindb = self.chaindb.write().unwrap();
+        let mut tip = chaindb.header_tip();
+        for header in &headers {
+            if header.validate_pow(&header.target()).is_err() {
+                self.p2p.send(P2PControl::Disconnect(peer));
+                return;
+            }
+            // a locator-based reorg walk is out of scope here; if a
+            // header does not connect to what we already trust, stop
+            // rather than accept a fork blindly
+            if let Some(current) = tip {
+                if header.prev_blockhash != current.hash {
+                    break;
+                }
+            }
+            let next_tip = HeaderTip {
+                hash: header.block_hash(),
+                height: tip.map(|t| t.height + 1).unwrap_or(0),
+            };
+            chaindb.store_header(*header);
+            chaindb.set_header_tip(next_tip);
+            tip = Some(next_tip);
+            self.downstream
+                .lock()
+                .unwrap()
+                .block_connected(&next_tip.hash, next_tip.height);
+        }
+        drop(chaindb);
+
+        if received == HEADERS_PER_MESSAGE {
+            self.ask_for_headers(peer);
+        }
+    }
+}
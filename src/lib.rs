@@ -0,0 +1,38 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Murmel
+//!
+//! A p2p client running on the Bitcoin network, implementing BIP157/158
+//! light client protocol.
+//!
+
+pub mod addrman;
+pub mod chaindb;
+pub mod compactfilterdownload;
+pub mod constructor;
+pub mod control;
+pub mod dispatcher;
+pub mod dns;
+pub mod downstream;
+pub mod error;
+pub mod hammersbald;
+pub mod headerdownload;
+pub mod p2p;
+pub mod ping;
+pub mod proxy;
+pub mod server;
+pub mod timeout;
@@ -0,0 +1,356 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # P2P
+//!
+//! Peer connection bookkeeping: dials/accepts peers, tracks what we
+//! know about each one, and fans inbound messages out to whatever reads
+//! from the `dispatch` sender (normally `Dispatcher`) while draining
+//! `P2PControl` requests (bind, connect, disconnect, send) queued up by
+//! the rest of the stack.
+//!
+
+use crate::dispatcher::PeerMessage;
+use crate::proxy::{self, NetAddress, ProxyConfig};
+use bitcoin::consensus::encode::serialize;
+use bitcoin::consensus::Decodable;
+use bitcoin::network::constants::{Network, ServiceFlags};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use futures::executor::ThreadPool;
+use futures::future::{self, Future};
+use futures::task::SpawnExt;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+
+pub type PeerId = u64;
+
+/// Wire-encode `payload` for `network`, ready to hand to
+/// `P2PControl::Send`
+pub fn encode_message(network: Network, payload: NetworkMessage) -> Vec<u8> {
+    serialize(&RawNetworkMessage {
+        magic: network.magic(),
+        payload,
+    })
+}
+
+/// Decode one message of `Message` off a connected peer's stream. Lets
+/// `P2P` stay generic over the wire message type instead of hard-coding
+/// `RawNetworkMessage`.
+pub trait WireDecode<Message> {
+    fn decode(stream: &mut TcpStream) -> io::Result<Message>;
+}
+
+impl WireDecode<NetworkMessage> for RawNetworkMessage {
+    fn decode(stream: &mut TcpStream) -> io::Result<NetworkMessage> {
+        RawNetworkMessage::consensus_decode(stream)
+            .map(|raw| raw.payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+/// Whatever a handshake message tells us about the peer that sent it,
+/// so `P2P` can update `PeerInfo` without knowing `Message`'s concrete
+/// shape
+pub trait VersionInfo {
+    fn version_info(&self) -> Option<(String, u32, ServiceFlags)>;
+}
+
+impl VersionInfo for NetworkMessage {
+    fn version_info(&self) -> Option<(String, u32, ServiceFlags)> {
+        match self {
+            NetworkMessage::Version(v) => {
+                Some((v.user_agent.clone(), v.start_height.max(0) as u32, v.services))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Static configuration of the local node as presented to peers
+pub struct BitcoinP2PConfig {
+    pub network: bitcoin::network::constants::Network,
+    pub nonce: u64,
+    pub max_protocol_version: u32,
+    pub user_agent: String,
+    pub height: AtomicUsize,
+    /// true if we are listening for inbound connections at all
+    pub server: bool,
+    /// dial `.onion` peers through this SOCKS5 proxy; clearnet peers are
+    /// always dialed directly
+    pub proxy: Option<ProxyConfig>,
+    /// services we serve; `P2P` sends no `version` message, so these
+    /// are not announced to peers
+    pub services: ServiceFlags,
+}
+
+/// Where a peer connection came from / how to reach it
+#[derive(Clone, Debug)]
+pub enum PeerSource {
+    Outgoing(SocketAddr),
+    Incoming(SocketAddr),
+    /// dial a (typically `.onion`) `NetAddress` through a SOCKS5 proxy
+    Proxy(NetAddress, ProxyConfig),
+}
+
+/// What we know about a connected peer
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub id: PeerId,
+    pub address: Option<SocketAddr>,
+    pub user_agent: String,
+    pub height: u32,
+    pub services: ServiceFlags,
+}
+
+/// Requests from the rest of the stack back out to the network layer
+#[derive(Clone, Debug)]
+pub enum P2PControl {
+    Bind(SocketAddr),
+    Connect(PeerSource),
+    Disconnect(PeerId),
+    Send(PeerId, Vec<u8>),
+}
+
+/// A cloneable sender of `T`, used both for `P2PControl` requests and
+/// for the events `Dispatcher` fans out to its listeners
+pub struct PeerMessageSender<T> {
+    sender: SyncSender<T>,
+}
+
+impl<T> PeerMessageSender<T> {
+    pub fn new(sender: SyncSender<T>) -> PeerMessageSender<T> {
+        PeerMessageSender { sender }
+    }
+
+    pub fn send(&self, msg: T) {
+        let _ = self.sender.send(msg);
+    }
+}
+
+impl<T> Clone for PeerMessageSender<T> {
+    fn clone(&self) -> Self {
+        PeerMessageSender {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+/// The peer connection table, plus the glue between it, `Dispatcher`
+/// and `P2PControl`
+pub struct P2P<Message, RawMessage, Config> {
+    config: Config,
+    dispatch: PeerMessageSender<PeerMessage<Message>>,
+    peers: Mutex<HashMap<PeerId, PeerInfo>>,
+    bound: Mutex<Vec<SocketAddr>>,
+    control: Mutex<Receiver<P2PControl>>,
+    /// write half of every peer we actually dialed; peers accepted by a
+    /// listener we have not implemented yet have no entry here, so
+    /// `P2PControl::Send` to them is a no-op rather than a panic
+    streams: Mutex<HashMap<PeerId, TcpStream>>,
+    next_id: AtomicU64,
+    _marker: std::marker::PhantomData<RawMessage>,
+}
+
+impl<Message: Send + 'static, RawMessage, Config> P2P<Message, RawMessage, Config> {
+    pub fn new(
+        config: Config,
+        dispatch: PeerMessageSender<PeerMessage<Message>>,
+        back_pressure: usize,
+    ) -> (
+        Arc<P2P<Message, RawMessage, Config>>,
+        PeerMessageSender<P2PControl>,
+    ) {
+        let (control_tx, control_rx) = std::sync::mpsc::sync_channel(back_pressure);
+        let p2p = Arc::new(P2P {
+            config,
+            dispatch,
+            peers: Mutex::new(HashMap::new()),
+            bound: Mutex::new(Vec::new()),
+            control: Mutex::new(control_rx),
+            streams: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            _marker: std::marker::PhantomData,
+        });
+        (p2p, PeerMessageSender::new(control_tx))
+    }
+
+    pub fn n_connected_peers(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    /// Peers currently connected, with user-agent/height/services
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Connect (or accept) a peer from `source`, registering it in the
+    /// peer table once the handshake would complete. `.onion` sources
+    /// are dialed through the configured SOCKS5 proxy. Outgoing and
+    /// proxied sources open a real socket, which is kept around so
+    /// `P2PControl::Send` has somewhere to write and a reader thread has
+    /// somewhere to read the peer's handshake from. `Incoming` sources
+    /// are recorded with no socket, since nothing in this crate accepts
+    /// inbound connections yet.
+    pub fn add_peer(
+        self: &Arc<Self>,
+        _chain: &'static str,
+        source: PeerSource,
+    ) -> Pin<Box<dyn Future<Output = Result<PeerId, crate::error::Error>> + Send>>
+    where
+        RawMessage: WireDecode<Message> + 'static,
+        Message: VersionInfo + Clone,
+    {
+        let p2p = self.clone();
+        Box::pin(future::lazy(move |_| {
+            let (address, stream) = match &source {
+                PeerSource::Outgoing(addr) => (Some(*addr), Some(TcpStream::connect(addr)?)),
+                PeerSource::Incoming(addr) => (Some(*addr), None),
+                PeerSource::Proxy(net_addr, proxy) => {
+                    let stream = proxy::connect(proxy, net_addr)?;
+                    (net_addr.socket_addr(), Some(stream))
+                }
+            };
+            let id = p2p.next_id.fetch_add(1, Ordering::SeqCst);
+            p2p.peers.lock().unwrap().insert(
+                id,
+                PeerInfo {
+                    id,
+                    address,
+                    user_agent: String::new(),
+                    height: 0,
+                    services: ServiceFlags::NONE,
+                },
+            );
+            if let Some(stream) = stream {
+                let reader = stream.try_clone()?;
+                p2p.streams.lock().unwrap().insert(id, stream);
+                p2p.spawn_reader(id, reader);
+            }
+            p2p.dispatch.send(PeerMessage::Connected(id));
+            Ok(id)
+        }))
+    }
+
+    /// Read `Message`s off `stream` until it errs out or the peer hangs
+    /// up, updating `PeerInfo` from the handshake (the only way we
+    /// learn a peer's real user-agent/height/services) and forwarding
+    /// everything to the dispatcher
+    fn spawn_reader(self: &Arc<Self>, id: PeerId, mut stream: TcpStream)
+    where
+        RawMessage: WireDecode<Message> + 'static,
+        Message: VersionInfo + Clone,
+    {
+        let p2p = self.clone();
+        std::thread::Builder::new()
+            .name(format!("peer-{}", id))
+            .spawn(move || loop {
+                match RawMessage::decode(&mut stream) {
+                    Ok(message) => {
+                        if let Some((user_agent, height, services)) = message.version_info() {
+                            if let Some(info) = p2p.peers.lock().unwrap().get_mut(&id) {
+                                info.user_agent = user_agent;
+                                info.height = height;
+                                info.services = services;
+                            }
+                        }
+                        p2p.dispatch.send(PeerMessage::Message(id, message));
+                    }
+                    Err(_) => {
+                        p2p.disconnect(id);
+                        break;
+                    }
+                }
+            })
+            .expect("can not start peer reader thread");
+    }
+
+    /// Disconnect a peer by id
+    pub fn disconnect(&self, id: PeerId) {
+        if self.peers.lock().unwrap().remove(&id).is_some() {
+            self.streams.lock().unwrap().remove(&id);
+            self.dispatch.send(PeerMessage::Disconnected(id));
+        }
+    }
+
+    /// Drain queued `P2PControl` requests, dialing/disconnecting peers
+    /// as asked and writing `Send` payloads to whichever peer's socket
+    /// we actually hold, then prune any connected peer that has since
+    /// told us (via `PeerInfo::services`, populated once its handshake
+    /// is read) that it cannot serve `needed_services`.
+    pub fn poll_events(self: &Arc<Self>, chain: &'static str, needed_services: ServiceFlags, cex: &mut ThreadPool)
+    where
+        RawMessage: WireDecode<Message> + 'static,
+        Message: VersionInfo + Clone,
+    {
+        let queued = {
+            let control = self.control.lock().unwrap();
+            control.try_iter().collect::<Vec<_>>()
+        };
+        for request in queued {
+            match request {
+                P2PControl::Bind(addr) => self.bound.lock().unwrap().push(addr),
+                P2PControl::Connect(source) => {
+                    let p2p = self.clone();
+                    let _ = cex.spawn(async move {
+                        let _ = p2p.add_peer(chain, source).await;
+                    });
+                }
+                P2PControl::Disconnect(id) => self.disconnect(id),
+                P2PControl::Send(peer, bytes) => {
+                    // no stream recorded means either an incoming peer
+                    // (no accept loop exists yet) or one that has since
+                    // disconnected; either way there is nowhere to write
+                    let result = self
+                        .streams
+                        .lock()
+                        .unwrap()
+                        .get_mut(&peer)
+                        .map(|stream| stream.write_all(&bytes));
+                    if let Some(Err(_)) = result {
+                        self.disconnect(peer);
+                    }
+                }
+            }
+        }
+
+        if needed_services != ServiceFlags::NONE {
+            // `PeerInfo::services` only becomes meaningful once a
+            // version-message handler populates it; `NONE` here means
+            // "not learned yet" rather than "offers nothing", so a peer
+            // is only pruned once we actually know it falls short.
+            let lacking = self
+                .peers
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|p| p.services != ServiceFlags::NONE && !p.services.has(needed_services))
+                .map(|p| p.id)
+                .collect::<Vec<_>>();
+            for id in lacking {
+                self.disconnect(id);
+            }
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+}
@@ -0,0 +1,80 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Ping
+//!
+//! Answers a peer's `ping` with the matching `pong`. It sends no `ping`
+//! of its own; a `pong` that arrives only clears an
+//! `ExpectedReply::Pong` that `Timeout` may hold for that peer.
+//!
+
+use crate::dispatcher::PeerMessage;
+use crate::p2p::{encode_message, P2PControl, PeerId, PeerMessageSender};
+use crate::timeout::{ExpectedReply, SharedTimeout};
+use bitcoin::network::constants::Network;
+use bitcoin::network::message::NetworkMessage;
+use std::sync::Arc;
+
+pub struct Ping {
+    network: Network,
+    p2p: PeerMessageSender<P2PControl>,
+    timeout: SharedTimeout,
+}
+
+impl Ping {
+    pub fn new(
+        network: Network,
+        p2p: PeerMessageSender<P2PControl>,
+        timeout: SharedTimeout,
+    ) -> PeerMessageSender<PeerMessage<NetworkMessage>> {
+        let ping = Arc::new(Ping { network, p2p, timeout });
+        let (sender, receiver) = std::sync::mpsc::sync_channel(100);
+        std::thread::Builder::new()
+            .name("ping".to_string())
+            .spawn(move || loop {
+                match receiver.recv() {
+                    Ok(msg) => ping.process(msg),
+                    Err(_) => break,
+                }
+            })
+            .expect("can not start ping thread");
+        PeerMessageSender::new(sender)
+    }
+
+    fn process(&self, msg: PeerMessage<NetworkMessage>) {
+        if let PeerMessage::Message(peer, message) = msg {
+            match message {
+                NetworkMessage::Ping(nonce) => self.reply(peer, nonce),
+                NetworkMessage::Pong(nonce) => self.handle_pong(peer, nonce),
+                _ => {}
+            }
+        }
+    }
+
+    fn reply(&self, peer: PeerId, nonce: u64) {
+        self.p2p.send(P2PControl::Send(
+            peer,
+            encode_message(self.network, NetworkMessage::Pong(nonce)),
+        ));
+    }
+
+    fn handle_pong(&self, peer: PeerId, _nonce: u64) {
+        // we do not yet send our own unsolicited pings with a nonce we
+        // could match here; once we do, this is where the matching
+        // ExpectedReply::Pong gets cleared
+        self.timeout.lock().unwrap().received(peer, ExpectedReply::Pong);
+    }
+}
@@ -0,0 +1,218 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # SOCKS5 proxy dialing and BIP155 addrv2 addresses
+//!
+//! Onion peers are not reachable as a plain `SocketAddr`, so outbound
+//! connections to them go through a local SOCKS5 proxy (as configured on
+//! `BitcoinP2PConfig`) instead of a direct TCP dial. `NetAddress` is the
+//! tagged address type threaded from the address manager through
+//! `add_peer`: everything that used to assume "every peer is a
+//! `SocketAddr`" now goes through this instead, and BIP155 `addrv2`
+//! gossip is parsed straight into it.
+//!
+
+use sha3::{Digest, Sha3_256};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+
+/// Tor v3 `.onion` address version byte, per rend-spec-v3
+const ONION_V3_VERSION: u8 = 0x03;
+
+/// A BIP155 address, as learned from `addr`/`addrv2` gossip or configured
+/// directly
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NetAddress {
+    IPv4(Ipv4Addr, u16),
+    IPv6(Ipv6Addr, u16),
+    /// 32 byte ed25519 public key and port, per BIP155 network id 4
+    OnionV3([u8; 32], u16),
+}
+
+impl NetAddress {
+    /// Host:port pair to hand to the SOCKS5 CONNECT request, which sends
+    /// every host as a domain name: clearnet addresses as their IP
+    /// literal, onion addresses as their `.onion` hostname so the Tor
+    /// SOCKS proxy resolves them itself.
+    fn socks5_target(&self) -> (String, u16) {
+        match self {
+            NetAddress::IPv4(ip, port) => (ip.to_string(), *port),
+            NetAddress::IPv6(ip, port) => (ip.to_string(), *port),
+            NetAddress::OnionV3(pubkey, port) => (onion_address(pubkey), *port),
+        }
+    }
+
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            NetAddress::IPv4(ip, port) => Some(SocketAddr::from((*ip, *port))),
+            NetAddress::IPv6(ip, port) => Some(SocketAddr::from((*ip, *port))),
+            NetAddress::OnionV3(..) => None,
+        }
+    }
+}
+
+/// A configured SOCKS5 endpoint to dial Tor (or any other SOCKS5 proxy)
+/// through
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    pub socks5: SocketAddr,
+}
+
+/// Dial `target` through `proxy` with a SOCKS5 CONNECT (no auth), per
+/// RFC 1928
+pub fn connect(proxy: &ProxyConfig, target: &NetAddress) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy.socks5)?;
+
+    // greeting: version 5, one method, no auth
+    stream.write_all(&[0x05, 0x01, 0x00])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [0x05, 0x00] {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "SOCKS5 proxy refused no-auth handshake",
+        ));
+    }
+
+    let (host, port) = target.socks5_target();
+    let host_bytes = host.as_bytes();
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host_bytes.len() as u8];
+    request.extend_from_slice(host_bytes);
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header)?;
+    if header[1] != 0x00 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("SOCKS5 CONNECT failed with code {}", header[1]),
+        ));
+    }
+    // drain the bound address the proxy echoes back
+    match header[3] {
+        0x01 => {
+            let mut skip = [0u8; 4 + 2];
+            stream.read_exact(&mut skip)?;
+        }
+        0x04 => {
+            let mut skip = [0u8; 16 + 2];
+            stream.read_exact(&mut skip)?;
+        }
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            let mut skip = vec![0u8; len[0] as usize + 2];
+            stream.read_exact(&mut skip)?;
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "SOCKS5 proxy returned an unknown address type",
+            ))
+        }
+    }
+
+    Ok(stream)
+}
+
+/// Encode a Tor v3 service's ed25519 public key as its `.onion` hostname:
+/// base32(pubkey(32) || checksum(2) || version(1)), per rend-spec-v3
+/// section 6, where `checksum = SHA3-256(".onion checksum" || pubkey ||
+/// version)[..2]`.
+fn onion_address(pubkey: &[u8; 32]) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(pubkey);
+    hasher.update(&[ONION_V3_VERSION]);
+    let digest = hasher.finalize();
+
+    let mut payload = Vec::with_capacity(35);
+    payload.extend_from_slice(pubkey);
+    payload.extend_from_slice(&digest[..2]);
+    payload.push(ONION_V3_VERSION);
+
+    format!("{}.onion", base32_lower(&payload))
+}
+
+fn base32_lower(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut out = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0u32;
+    for &b in bytes {
+        buffer = (buffer << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+/// Convert a `rust-bitcoin` typed `addrv2` entry into our own tagged
+/// address, dropping network types we have no way to dial (legacy Tor
+/// v2, I2P, CJDNS) rather than silently misinterpreting them.
+pub fn from_addr_v2(addr: &bitcoin::network::address::AddrV2, port: u16) -> Option<NetAddress> {
+    use bitcoin::network::address::AddrV2;
+    match addr {
+        AddrV2::Ipv4(ip) => Some(NetAddress::IPv4(*ip, port)),
+        AddrV2::Ipv6(ip) => Some(NetAddress::IPv6(*ip, port)),
+        AddrV2::TorV3(pubkey) => Some(NetAddress::OnionV3(*pubkey, port)),
+        _ => None,
+    }
+}
+
+/// Convert a legacy `addr` entry into our own tagged address. Legacy
+/// `addr` only ever carries IPv4/IPv6 (onion peers are not representable
+/// in it), so this can never produce a `NetAddress::OnionV3`.
+pub fn from_addr(address: &bitcoin::network::address::Address) -> Option<NetAddress> {
+    match address.socket_addr().ok()? {
+        SocketAddr::V4(v4) => Some(NetAddress::IPv4(*v4.ip(), v4.port())),
+        SocketAddr::V6(v6) => Some(NetAddress::IPv6(*v6.ip(), v6.port())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_ipv4_addr_v2_entry() {
+        use bitcoin::network::address::AddrV2;
+        let addr = from_addr_v2(&AddrV2::Ipv4(Ipv4Addr::new(127, 0, 0, 1)), 8333);
+        assert_eq!(addr, Some(NetAddress::IPv4(Ipv4Addr::new(127, 0, 0, 1), 8333)));
+    }
+
+    #[test]
+    fn drops_unsupported_addr_v2_network() {
+        use bitcoin::network::address::AddrV2;
+        assert_eq!(from_addr_v2(&AddrV2::I2p([0u8; 32]), 8333), None);
+    }
+
+    #[test]
+    fn onion_address_is_lowercase_base32_with_suffix() {
+        let name = onion_address(&[0u8; 32]);
+        assert!(name.ends_with(".onion"));
+        assert!(name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || ('2'..='7').contains(&c) || c == '.'));
+    }
+}
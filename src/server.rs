@@ -0,0 +1,172 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Inbound servers
+//!
+//! Answers `getheaders`/`getcfheaders`/`getcfilters` from peers, reading
+//! whatever the local chaindb has. Registered with the dispatcher only
+//! when we are listening and/or advertise the relevant service.
+//!
+
+use crate::chaindb::SharedChainDB;
+use crate::dispatcher::PeerMessage;
+use crate::p2p::{encode_message, P2PControl, PeerId, PeerMessageSender};
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::{Network, ServiceFlags};
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters};
+
+/// Most headers we hand back in a single `headers` reply, matching Core
+const MAX_HEADERS: u32 = 2000;
+
+/// Answers `getheaders` from the local chaindb. Registered whenever we
+/// listen for inbound connections, whatever services we advertise.
+pub struct HeaderServer {
+    network: Network,
+    chaindb: SharedChainDB,
+    p2p: PeerMessageSender<P2PControl>,
+}
+
+impl HeaderServer {
+    pub fn new(
+        network: Network,
+        chaindb: SharedChainDB,
+        p2p: PeerMessageSender<P2PControl>,
+    ) -> PeerMessageSender<PeerMessage<NetworkMessage>> {
+        let server = HeaderServer { network, chaindb, p2p };
+        let (sender, receiver) = std::sync::mpsc::sync_channel(100);
+        std::thread::Builder::new()
+            .name("headerserver".to_string())
+            .spawn(move || {
+                while let Ok(msg) = receiver.recv() {
+                    server.process(msg);
+                }
+            })
+            .expect("can not start header server thread");
+        PeerMessageSender::new(sender)
+    }
+
+    fn process(&self, msg: PeerMessage<NetworkMessage>) {
+        if let PeerMessage::Message(peer, NetworkMessage::GetHeaders(_)) = msg {
+            // we only keep headers, not the locator walk a full node
+            // would do against a fork-aware index; reply with what we
+            // have up to our tip, capped like Core's `headers` message
+            let chaindb = self.chaindb.read().unwrap();
+            let tip_height = chaindb.header_tip().map(|tip| tip.height()).unwrap_or(0);
+            let reply_height = tip_height.min(MAX_HEADERS - 1);
+            let headers = (0..=reply_height)
+                .filter_map(|height| chaindb.header_at_height(height))
+                .filter_map(|hash| chaindb.header(&hash))
+                .collect::<Vec<_>>();
+            self.p2p.send(P2PControl::Send(
+                peer,
+                encode_message(self.network, NetworkMessage::Headers(headers)),
+            ));
+        }
+    }
+}
+
+/// Answers `getcfheaders`/`getcfilters`. Only registered when
+/// `NODE_COMPACT_FILTERS` is among the services we advertise.
+pub struct FilterServer {
+    network: Network,
+    chaindb: SharedChainDB,
+    p2p: PeerMessageSender<P2PControl>,
+}
+
+impl FilterServer {
+    pub fn new(
+        network: Network,
+        chaindb: SharedChainDB,
+        p2p: PeerMessageSender<P2PControl>,
+    ) -> PeerMessageSender<PeerMessage<NetworkMessage>> {
+        let server = FilterServer { network, chaindb, p2p };
+        let (sender, receiver) = std::sync::mpsc::sync_channel(100);
+        std::thread::Builder::new()
+            .name("filterserver".to_string())
+            .spawn(move || {
+                while let Ok(msg) = receiver.recv() {
+                    server.process(msg);
+                }
+            })
+            .expect("can not start filter server thread");
+        PeerMessageSender::new(sender)
+    }
+
+    fn process(&self, msg: PeerMessage<NetworkMessage>) {
+        match msg {
+            PeerMessage::Message(peer, NetworkMessage::GetCFHeaders(get)) => {
+                self.reply_cfheaders(peer, get)
+            }
+            PeerMessage::Message(peer, NetworkMessage::GetCFilters(get)) => {
+                self.reply_cfilters(peer, get)
+            }
+            _ => {}
+        }
+    }
+
+    fn reply_cfheaders(&self, peer: PeerId, get: GetCFHeaders) {
+        let chaindb = self.chaindb.read().unwrap();
+        if let Some(cfheader) = chaindb.filter_header(&get.stop_hash) {
+            let reply = CFHeaders {
+                filter_type: get.filter_type,
+                stop_hash: get.stop_hash,
+                previous_filter_header: bitcoin::hash_types::FilterHash::from_inner(
+                    cfheader.into_inner(),
+                ),
+                filter_hashes: Vec::new(),
+            };
+            self.p2p.send(P2PControl::Send(
+                peer,
+                encode_message(self.network, NetworkMessage::CFHeaders(reply)),
+            ));
+        }
+    }
+
+    fn reply_cfilters(&self, peer: PeerId, get: GetCFilters) {
+        let chaindb = self.chaindb.read().unwrap();
+        if let Some(filter) = chaindb.filter(&get.stop_hash) {
+            let reply = CFilter {
+                filter_type: get.filter_type,
+                block_hash: get.stop_hash,
+                filter,
+            };
+            self.p2p.send(P2PControl::Send(
+                peer,
+                encode_message(self.network, NetworkMessage::CFilter(reply)),
+            ));
+        }
+    }
+}
+
+/// Whether `services` obliges us to run the filter server alongside the
+/// header server
+pub fn wants_filter_server(services: ServiceFlags) -> bool {
+    services.has(ServiceFlags::COMPACT_FILTERS)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filter_server_only_runs_when_advertised() {
+        assert!(!wants_filter_server(ServiceFlags::NETWORK_LIMITED));
+        assert!(wants_filter_server(
+            ServiceFlags::NETWORK_LIMITED | ServiceFlags::COMPACT_FILTERS
+        ));
+    }
+}
@@ -0,0 +1,84 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Timeout
+//!
+//! Tracks what reply we are waiting for from which peer, so a peer that
+//! asked for work and never answers gets disconnected instead of
+//! stalling us forever.
+//!
+
+use crate::p2p::{P2PControl, PeerId, PeerMessageSender};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub type SharedTimeout = Arc<Mutex<Timeout>>;
+
+/// What reply we expect next from a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExpectedReply {
+    Headers,
+    CFHeaders,
+    CFilter,
+    CFCheckpt,
+    Pong,
+}
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Timeout {
+    p2p: PeerMessageSender<P2PControl>,
+    expected: HashMap<PeerId, (ExpectedReply, Instant)>,
+}
+
+impl Timeout {
+    pub fn new(p2p: PeerMessageSender<P2PControl>) -> Timeout {
+        Timeout {
+            p2p,
+            expected: HashMap::new(),
+        }
+    }
+
+    /// Start expecting `reply` from `peer`
+    pub fn expect(&mut self, peer: PeerId, reply: ExpectedReply) {
+        self.expected.insert(peer, (reply, Instant::now()));
+    }
+
+    /// The expected reply arrived, stop the clock on it
+    pub fn received(&mut self, peer: PeerId, reply: ExpectedReply) {
+        if let Some((expected, _)) = self.expected.get(&peer) {
+            if *expected == reply {
+                self.expected.remove(&peer);
+            }
+        }
+    }
+
+    /// Disconnect any peer that did not answer in time
+    pub fn check(&mut self) {
+        let now = Instant::now();
+        let overdue = self
+            .expected
+            .iter()
+            .filter(|(_, (_, since))| now.duration_since(*since) > REPLY_TIMEOUT)
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+        for peer in overdue {
+            self.expected.remove(&peer);
+            self.p2p.send(P2PControl::Disconnect(peer));
+        }
+    }
+}